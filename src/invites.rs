@@ -0,0 +1,187 @@
+/*
+ * Copyright (c) 2025 Matteo Franceschini
+ * All rights reserved.
+ *
+ * Use of this source code is governed by BSD-3-Clause-Clear
+ * license that can be found in the LICENSE file
+ */
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+/// A single-use invite code, optionally expirable.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Invite {
+    /// Unix timestamp (seconds) after which the code is no longer accepted.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub used: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum InviteError {
+    #[error("Unknown invite code")]
+    Unknown,
+    #[error("This invite code has already been used")]
+    Used,
+    #[error("This invite code has expired")]
+    Expired,
+}
+
+/// Configured set of one-time invite codes, persisted alongside the user
+/// store so consumed codes stay consumed across restarts.
+pub struct InviteStore {
+    path: PathBuf,
+    invites: Mutex<HashMap<String, Invite>>,
+}
+
+impl InviteStore {
+    /// Load the invite allowlist from `path`, starting empty (and thus
+    /// rejecting every enrollment) when the file is missing or unreadable.
+    pub async fn load<P: AsRef<Path>>(path: P) -> InviteStore {
+        let path = path.as_ref().to_path_buf();
+        let invites = match tokio::fs::read_to_string(&path).await {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                warn!("Could not parse invites file, starting empty: {e}");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        InviteStore {
+            path,
+            invites: Mutex::new(invites),
+        }
+    }
+
+    async fn persist(&self, invites: &HashMap<String, Invite>) {
+        match serde_json::to_string_pretty(invites) {
+            Ok(data) => {
+                if let Err(e) = tokio::fs::write(&self.path, data).await {
+                    error!("Could not persist invites file: {e}");
+                }
+            }
+            Err(e) => error!("Could not serialize invites: {e}"),
+        }
+    }
+
+    /// Atomically validate and claim a code under a single lock, so concurrent
+    /// enrollments can't both consume the same single-use code. The claim can
+    /// be undone with [`release`](Self::release) if a later step fails.
+    pub async fn claim(&self, code: &str) -> Result<(), InviteError> {
+        let mut invites = self.invites.lock().await;
+        let invite = invites.get_mut(code).ok_or(InviteError::Unknown)?;
+        if invite.used {
+            return Err(InviteError::Used);
+        }
+        if let Some(expires_at) = invite.expires_at {
+            if now() >= expires_at {
+                return Err(InviteError::Expired);
+            }
+        }
+        invite.used = true;
+        let snapshot = invites.clone();
+        drop(invites);
+        self.persist(&snapshot).await;
+        Ok(())
+    }
+
+    /// Return a previously-[`claim`](Self::claim)ed code to the unused state,
+    /// used to roll back when enrollment fails after the claim.
+    pub async fn release(&self, code: &str) {
+        let mut invites = self.invites.lock().await;
+        if let Some(invite) = invites.get_mut(code) {
+            invite.used = false;
+        }
+        let snapshot = invites.clone();
+        drop(invites);
+        self.persist(&snapshot).await;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(path: &str, invites: HashMap<String, Invite>) -> InviteStore {
+        InviteStore {
+            path: std::env::temp_dir().join(path),
+            invites: Mutex::new(invites),
+        }
+    }
+
+    fn with_invite(code: &str, invite: Invite) -> HashMap<String, Invite> {
+        let mut map = HashMap::new();
+        map.insert(code.to_owned(), invite);
+        map
+    }
+
+    #[tokio::test]
+    async fn code_can_only_be_claimed_once() {
+        let store = store(
+            "readeckbot-test-single-use.json",
+            with_invite(
+                "abc",
+                Invite {
+                    expires_at: None,
+                    used: false,
+                },
+            ),
+        );
+        assert!(store.claim("abc").await.is_ok());
+        assert!(matches!(store.claim("abc").await, Err(InviteError::Used)));
+    }
+
+    #[tokio::test]
+    async fn expired_code_is_rejected() {
+        let store = store(
+            "readeckbot-test-expired.json",
+            with_invite(
+                "old",
+                Invite {
+                    expires_at: Some(0),
+                    used: false,
+                },
+            ),
+        );
+        assert!(matches!(store.claim("old").await, Err(InviteError::Expired)));
+    }
+
+    #[tokio::test]
+    async fn unknown_code_is_rejected() {
+        let store = store("readeckbot-test-unknown.json", HashMap::new());
+        assert!(matches!(store.claim("nope").await, Err(InviteError::Unknown)));
+    }
+
+    #[tokio::test]
+    async fn release_restores_a_claimed_code() {
+        let store = store(
+            "readeckbot-test-release.json",
+            with_invite(
+                "xyz",
+                Invite {
+                    expires_at: None,
+                    used: false,
+                },
+            ),
+        );
+        assert!(store.claim("xyz").await.is_ok());
+        store.release("xyz").await;
+        assert!(store.claim("xyz").await.is_ok());
+    }
+}
@@ -5,10 +5,14 @@
  * Use of this source code is governed by BSD-3-Clause-Clear
  * license that can be found in the LICENSE file
  */
+use std::str::FromStr;
+
 use reqwest::{Client, StatusCode, Url};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+pub mod invites;
+pub mod queue;
 pub mod users;
 
 #[derive(Serialize)]
@@ -17,13 +21,13 @@ pub struct BookmarkCreateRequest {
     url: Url,
 }
 
+/// The label attached to every bookmark saved through the bot.
+pub const BOT_LABEL: &str = "readeck-bot";
+
 impl BookmarkCreateRequest {
-    pub fn new(url: Url, bot_mark: bool) -> BookmarkCreateRequest {
-        let labels = if bot_mark {
-            vec!["readeck-bot".to_owned()]
-        } else {
-            vec![]
-        };
+    /// Create a request with an explicit label set. Callers decide which
+    /// labels to attach — the fixed [`BOT_LABEL`] is just one of them.
+    pub fn new(url: Url, labels: Vec<String>) -> BookmarkCreateRequest {
         BookmarkCreateRequest { labels, url }
     }
 }
@@ -34,6 +38,42 @@ pub struct BookmarkDetailsResponse {
     pub reading_time: Option<u32>,
 }
 
+/// A single entry in a bookmark listing.
+#[derive(Deserialize)]
+pub struct BookmarkSummary {
+    pub id: String,
+    pub title: String,
+    pub url: String,
+}
+
+/// Artifact formats Readeck can export an article to.
+#[derive(Clone, Copy)]
+pub enum ExportFormat {
+    Epub,
+    Pdf,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Epub => "epub",
+            ExportFormat::Pdf => "pdf",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ExportFormat, ()> {
+        match s.to_lowercase().as_str() {
+            "epub" => Ok(ExportFormat::Epub),
+            "pdf" => Ok(ExportFormat::Pdf),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ReadeckError {
     #[error("The request token found in the Authorization header is not valid")]
@@ -54,6 +94,19 @@ pub enum ReadeckError {
     MissingBookmarkId,
 }
 
+impl ReadeckError {
+    /// Whether the failure is transient and worth re-dispatching: network
+    /// errors and 5xx responses from Readeck, but not client-side errors such
+    /// as bad credentials or invalid data.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ReadeckError::ReqwestError(_) => true,
+            ReadeckError::OtherHttp(status) => *status >= 500,
+            _ => false,
+        }
+    }
+}
+
 pub struct ReadeckApi {
     client: Client,
     server_url: Url,
@@ -64,19 +117,58 @@ impl ReadeckApi {
         let client = reqwest::Client::new();
         ReadeckApi { client, server_url }
     }
+    /// Report a failed Readeck call to Sentry, tagged with the server URL and —
+    /// when the failure came from an HTTP response — the status code. A no-op
+    /// when Sentry is not initialized.
+    fn report(&self, error: &ReadeckError, status: Option<u16>) {
+        sentry::with_scope(
+            |scope| {
+                scope.set_tag("server_url", self.server_url.as_str());
+                if let Some(status) = status {
+                    scope.set_tag("http_status", status.to_string());
+                }
+            },
+            || {
+                sentry::capture_error(error);
+            },
+        );
+    }
     pub async fn save_url(
         &self,
         url: Url,
         api_token: &str,
         bot_mark: bool,
+    ) -> Result<String, ReadeckError> {
+        let labels = if bot_mark {
+            vec![BOT_LABEL.to_owned()]
+        } else {
+            vec![]
+        };
+        self.create_bookmark(url, api_token, labels).await
+    }
+    /// Save a URL with a caller-supplied label set, used by the `/label`
+    /// command to attach arbitrary labels beyond the fixed bot marker.
+    pub async fn save_url_with_labels(
+        &self,
+        url: Url,
+        api_token: &str,
+        labels: Vec<String>,
+    ) -> Result<String, ReadeckError> {
+        self.create_bookmark(url, api_token, labels).await
+    }
+    async fn create_bookmark(
+        &self,
+        url: Url,
+        api_token: &str,
+        labels: Vec<String>,
     ) -> Result<String, ReadeckError> {
         let endpoint = self
             .server_url
             .join("/api/bookmarks")
             .expect("Malformed server url");
-        let body = BookmarkCreateRequest::new(url, bot_mark);
+        let body = BookmarkCreateRequest::new(url, labels);
         let body = serde_json::to_string(&body).map_err(ReadeckError::SerdeError)?;
-        let response = self
+        let response = match self
             .client
             .post(endpoint)
             .bearer_auth(api_token)
@@ -84,8 +176,16 @@ impl ReadeckApi {
             .body(body)
             .send()
             .await
-            .map_err(ReadeckError::ReqwestError)?;
-        match response.status() {
+        {
+            Ok(response) => response,
+            Err(e) => {
+                let error = ReadeckError::ReqwestError(e);
+                self.report_save_failure(&error, None);
+                return Err(error);
+            }
+        };
+        let status = response.status();
+        let result = match status {
             StatusCode::ACCEPTED => {
                 let bookmark_id = response
                     .headers()
@@ -99,7 +199,53 @@ impl ReadeckApi {
             StatusCode::FORBIDDEN => Err(ReadeckError::Forbidden),
             StatusCode::UNPROCESSABLE_ENTITY => Err(ReadeckError::InvalidData),
             status_code => Err(ReadeckError::OtherHttp(status_code.as_u16())),
+        };
+        if let Err(error) = &result {
+            self.report_save_failure(error, Some(status.as_u16()));
         }
+        result
+    }
+    /// Like [`report`](Self::report), but retryable save failures are skipped:
+    /// callers hand them to the retry queue, which captures them once when it
+    /// finally gives up instead of once per attempt during a single outage.
+    fn report_save_failure(&self, error: &ReadeckError, status: Option<u16>) {
+        if !error.is_retryable() {
+            self.report(error, status);
+        }
+    }
+    /// Probe whether an API token is accepted by Readeck by calling an
+    /// authenticated endpoint, used to validate tokens supplied at enrollment.
+    pub async fn validate_token(&self, api_token: &str) -> Result<(), ReadeckError> {
+        let endpoint = self
+            .server_url
+            .join("/api/profile")
+            .expect("Malformed server url");
+        let response = match self
+            .client
+            .get(endpoint)
+            .bearer_auth(api_token)
+            .header("Content-Type", "application/json")
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                let error = ReadeckError::ReqwestError(e);
+                self.report(&error, None);
+                return Err(error);
+            }
+        };
+        let status = response.status();
+        let result = match status {
+            StatusCode::OK => Ok(()),
+            StatusCode::UNAUTHORIZED => Err(ReadeckError::Unauthorized),
+            StatusCode::FORBIDDEN => Err(ReadeckError::Forbidden),
+            status_code => Err(ReadeckError::OtherHttp(status_code.as_u16())),
+        };
+        if let Err(error) = &result {
+            self.report(error, Some(status.as_u16()));
+        }
+        result
     }
     pub async fn get_bookmark_details(
         &self,
@@ -108,15 +254,23 @@ impl ReadeckApi {
     ) -> Result<BookmarkDetailsResponse, ReadeckError> {
         let path = format!("/api/bookmarks/{id}");
         let endpoint = self.server_url.join(&path).expect("Malformed server url");
-        let response = self
+        let response = match self
             .client
             .get(endpoint)
             .bearer_auth(api_token)
             .header("Content-Type", "application/json")
             .send()
             .await
-            .map_err(ReadeckError::ReqwestError)?;
-        match response.status() {
+        {
+            Ok(response) => response,
+            Err(e) => {
+                let error = ReadeckError::ReqwestError(e);
+                self.report(&error, None);
+                return Err(error);
+            }
+        };
+        let status = response.status();
+        let result = match status {
             StatusCode::OK => {
                 let response = response.text().await.map_err(ReadeckError::ReqwestError)?;
                 let bookmark_details: BookmarkDetailsResponse =
@@ -126,6 +280,95 @@ impl ReadeckApi {
             StatusCode::UNAUTHORIZED => Err(ReadeckError::Unauthorized),
             StatusCode::FORBIDDEN => Err(ReadeckError::Forbidden),
             status_code => Err(ReadeckError::OtherHttp(status_code.as_u16())),
+        };
+        if let Err(error) = &result {
+            self.report(error, Some(status.as_u16()));
+        }
+        result
+    }
+    /// List the user's most recent bookmarks, newest first.
+    pub async fn list_bookmarks(
+        &self,
+        api_token: &str,
+        limit: u32,
+    ) -> Result<Vec<BookmarkSummary>, ReadeckError> {
+        let path = format!("/api/bookmarks?limit={limit}&sort=-created");
+        let endpoint = self.server_url.join(&path).expect("Malformed server url");
+        let response = match self
+            .client
+            .get(endpoint)
+            .bearer_auth(api_token)
+            .header("Content-Type", "application/json")
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                let error = ReadeckError::ReqwestError(e);
+                self.report(&error, None);
+                return Err(error);
+            }
+        };
+        let status = response.status();
+        let result = match status {
+            StatusCode::OK => {
+                let response = response.text().await.map_err(ReadeckError::ReqwestError)?;
+                let bookmarks: Vec<BookmarkSummary> =
+                    serde_json::from_str(&response).map_err(ReadeckError::SerdeError)?;
+                Ok(bookmarks)
+            }
+            StatusCode::UNAUTHORIZED => Err(ReadeckError::Unauthorized),
+            StatusCode::FORBIDDEN => Err(ReadeckError::Forbidden),
+            status_code => Err(ReadeckError::OtherHttp(status_code.as_u16())),
+        };
+        if let Err(error) = &result {
+            self.report(error, Some(status.as_u16()));
+        }
+        result
+    }
+    /// Fetch a bookmark's exported article artifact, returning the raw bytes
+    /// and the `Content-Type` reported by Readeck.
+    pub async fn export_bookmark(
+        &self,
+        id: &str,
+        api_token: &str,
+        format: ExportFormat,
+    ) -> Result<(Vec<u8>, String), ReadeckError> {
+        let path = format!("/api/bookmarks/{id}/article.{}", format.extension());
+        let endpoint = self.server_url.join(&path).expect("Malformed server url");
+        let response = match self
+            .client
+            .get(endpoint)
+            .bearer_auth(api_token)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                let error = ReadeckError::ReqwestError(e);
+                self.report(&error, None);
+                return Err(error);
+            }
+        };
+        let status = response.status();
+        let result = match status {
+            StatusCode::OK => {
+                let content_type = response
+                    .headers()
+                    .get("Content-Type")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("application/octet-stream")
+                    .to_owned();
+                let bytes = response.bytes().await.map_err(ReadeckError::ReqwestError)?;
+                Ok((bytes.to_vec(), content_type))
+            }
+            StatusCode::UNAUTHORIZED => Err(ReadeckError::Unauthorized),
+            StatusCode::FORBIDDEN => Err(ReadeckError::Forbidden),
+            status_code => Err(ReadeckError::OtherHttp(status_code.as_u16())),
+        };
+        if let Err(error) = &result {
+            self.report(error, Some(status.as_u16()));
         }
+        result
     }
 }
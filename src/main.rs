@@ -6,18 +6,30 @@
  * license that can be found in the LICENSE file
  */
 
-use std::{env, process::exit, sync::Arc};
+use std::{
+    collections::HashSet,
+    collections::hash_map::DefaultHasher,
+    env,
+    hash::{Hash, Hasher},
+    path::Path,
+    process::exit,
+    sync::Arc,
+};
 
+use futures::{StreamExt, stream};
 use log::{error, info, trace, warn};
 use readeckbot::{
-    ReadeckApi, ReadeckError,
-    users::{UserData, Users},
+    ExportFormat, ReadeckApi, ReadeckError,
+    invites::{InviteError, InviteStore},
+    queue::{RetryConfig, RetryQueue, RetryWorker},
+    users::{UserData, UserStore, build_store},
 };
 use regex::Regex;
 use reqwest::Url;
 use teloxide::{
     prelude::*,
-    types::{MessageEntityKind, ReplyParameters},
+    types::{InputFile, MessageEntityKind, ReplyParameters},
+    utils::command::BotCommands,
 };
 use thiserror::Error;
 
@@ -27,6 +39,8 @@ async fn main() {
         .filter_level(log::LevelFilter::Info)
         .parse_env("LOG_LEVEL")
         .init();
+    // Kept alive for the lifetime of the process so events are flushed on exit.
+    let _sentry_guard = init_sentry();
     let pretty_response: bool = env::var("PRETTY_RESPONSE")
         .unwrap_or("true".to_owned())
         .parse()
@@ -61,29 +75,84 @@ async fn main() {
             "users.json".to_owned()
         }
     };
-    let users = Users::load(users);
-    let users = Arc::new(users);
+    let queue_path = Path::new(&users).with_file_name("retry_queue.json");
+    let users = build_store(&users).await;
     let api = Arc::new(api);
     let bot = Bot::new(bot_token);
+    let bot_username = match bot.get_me().await {
+        Ok(me) => me.user.username.clone().unwrap_or_default(),
+        Err(e) => {
+            error!("Could not fetch bot info: {e}");
+            exit(1)
+        }
+    };
+    let bot_username = Arc::new(bot_username);
+    let invites = match env::var("INVITES_FILE") {
+        Ok(f) => f,
+        Err(_) => {
+            warn!("INVITES_FILE not set, default to invites.json");
+            "invites.json".to_owned()
+        }
+    };
+    let invites = Arc::new(InviteStore::load(invites).await);
+    let queue = Arc::new(RetryQueue::load(queue_path, RetryConfig::default()).await);
+    tokio::spawn(
+        RetryWorker::new(queue.clone(), api.clone(), users.clone(), bot.clone()).run(),
+    );
     let handler = Update::filter_message().endpoint(
         |bot: Bot,
          api: Arc<ReadeckApi>,
          pretty_r: Arc<bool>,
-         usr: Arc<Users>,
+         usr: Arc<dyn UserStore>,
+         invites: Arc<InviteStore>,
+         queue: Arc<RetryQueue>,
+         bot_username: Arc<String>,
          msg: Message| async move {
             if let Some(user) = &msg.from {
-                match usr.find(user.id.0) {
-                    Some(user_data) => {
+                let anon_id = anonymize_user(user.id.0);
+                sentry::add_breadcrumb(sentry::Breadcrumb {
+                    category: Some("telegram".to_owned()),
+                    message: Some(format!("update from {anon_id}")),
+                    ..Default::default()
+                });
+                // Enrollment is available to unauthenticated users; every other
+                // message still requires an existing account.
+                if let Some(args) = msg.text().and_then(|t| parse_enroll(t, &bot_username)) {
+                    let response = enroll_handler(&api, &usr, &invites, user.id.0, args).await;
+                    bot.send_message(msg.chat.id, response)
+                        .reply_parameters(ReplyParameters::new(msg.id))
+                        .await?;
+                    return respond(());
+                }
+                match usr.find(user.id.0).await {
+                    Ok(Some(user_data)) => {
                         trace!("New message from user with ID: {:?}", user.id);
-                        let response = match msg_handler(api, &msg, *pretty_r, user_data).await {
+                        // A recognized command short-circuits the URL-save path.
+                        if let Some(cmd) = msg
+                            .text()
+                            .and_then(|t| BotCommand::parse(t, bot_username.as_str()).ok())
+                        {
+                            command_handler(&bot, &api, &user_data, &msg, cmd, &queue, user.id.0)
+                                .await?;
+                            return respond(());
+                        }
+                        let response = match msg_handler(
+                            api, &msg, *pretty_r, user.id.0, &user_data, queue,
+                        )
+                        .await
+                        {
                             Ok(s) => s,
+                            // `NotAnUrl` is ordinary user input (a photo, a
+                            // greeting, an unknown command) — reply normally and
+                            // leave telemetry for real failures, which
+                            // `ReadeckApi::report` already captures.
                             Err(e) => format!("{e}"),
                         };
                         bot.send_message(msg.chat.id, response)
                             .reply_parameters(ReplyParameters::new(msg.id))
                             .await?;
                     }
-                    None => {
+                    Ok(None) => {
                         info!(
                             "Connection refused with unauthorized user with ID: {:?}",
                             user.id
@@ -92,6 +161,12 @@ async fn main() {
                             .reply_parameters(ReplyParameters::new(msg.id))
                             .await?;
                     }
+                    Err(e) => {
+                        error!("User store lookup failed for {:?}: {e}", user.id);
+                        bot.send_message(msg.chat.id, "Temporary error, please retry.")
+                            .reply_parameters(ReplyParameters::new(msg.id))
+                            .await?;
+                    }
                 }
             } else {
                 bot.send_message(msg.chat.id, "Unauthorized")
@@ -102,98 +177,448 @@ async fn main() {
         },
     );
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![api, pretty_response, users])
+        .dependencies(dptree::deps![
+            api,
+            pretty_response,
+            users,
+            invites,
+            queue,
+            bot_username
+        ])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
         .await;
 }
 
+/// How many bookmarks `/recent` returns when no count is given.
+const DEFAULT_RECENT: u32 = 10;
+
+/// Commands available to enrolled users, parsed before the URL-extraction
+/// fallback. `/enroll` is handled separately since it must work without an
+/// existing account.
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase")]
+enum BotCommand {
+    #[command(description = "list your most recent bookmarks")]
+    Recent(String),
+    #[command(description = "save a url with custom labels: /label <url> <labels...>")]
+    Label(String),
+    #[command(description = "export a bookmark: /export <id> epub|pdf", parse_with = "split")]
+    Export { id: String, format: String },
+}
+
+/// Execute a parsed [`BotCommand`], replying in the originating chat.
+async fn command_handler(
+    bot: &Bot,
+    api: &ReadeckApi,
+    user_data: &UserData,
+    msg: &Message,
+    cmd: BotCommand,
+    queue: &RetryQueue,
+    user_id: u64,
+) -> ResponseResult<()> {
+    match cmd {
+        BotCommand::Recent(arg) => {
+            let limit = arg.trim().parse::<u32>().unwrap_or(DEFAULT_RECENT);
+            let text = match api.list_bookmarks(&user_data.api_token, limit).await {
+                Ok(bookmarks) if bookmarks.is_empty() => "No bookmarks yet.".to_owned(),
+                Ok(bookmarks) => bookmarks
+                    .iter()
+                    .map(|b| format!("\u{2022} {} — {}", b.title, b.url))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Err(e) => format!("{e}"),
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+        }
+        BotCommand::Label(arg) => {
+            let mut parts = arg.split_whitespace();
+            let text = match parts.next().map(Url::parse) {
+                Some(Ok(url)) => {
+                    let mut labels: Vec<String> = parts.map(|s| s.to_owned()).collect();
+                    if user_data.bot_marked {
+                        labels.push(readeckbot::BOT_LABEL.to_owned());
+                    }
+                    match api
+                        .save_url_with_labels(url.clone(), &user_data.api_token, labels.clone())
+                        .await
+                    {
+                        Ok(_) => "Saved with your labels.".to_owned(),
+                        // Transient failures go to the retry queue carrying the
+                        // full label set, so the custom labels survive the retry.
+                        Err(e) if e.is_retryable() => {
+                            queue
+                                .enqueue(user_id, msg.chat.id.0, url, labels)
+                                .await;
+                            "Readeck is unreachable right now, queued for retry.".to_owned()
+                        }
+                        Err(e) => format!("{e}"),
+                    }
+                }
+                _ => "Usage: /label <url> <labels...>".to_owned(),
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+        }
+        BotCommand::Export { id, format } => match format.parse::<ExportFormat>() {
+            Ok(format) => match api.export_bookmark(&id, &user_data.api_token, format).await {
+                Ok((bytes, _content_type)) => {
+                    let filename = format!("{id}.{}", format.extension());
+                    bot.send_document(msg.chat.id, InputFile::memory(bytes).file_name(filename))
+                        .reply_parameters(ReplyParameters::new(msg.id))
+                        .await?;
+                }
+                Err(e) => {
+                    bot.send_message(msg.chat.id, format!("{e}"))
+                        .reply_parameters(ReplyParameters::new(msg.id))
+                        .await?;
+                }
+            },
+            Err(_) => {
+                bot.send_message(msg.chat.id, "Usage: /export <id> epub|pdf")
+                    .reply_parameters(ReplyParameters::new(msg.id))
+                    .await?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Recognize a `/enroll` command — matching `/enroll` and `/enroll@botname` on
+/// a word boundary, never `/enrollfoo` — and return its argument string.
+fn parse_enroll<'a>(text: &'a str, bot_username: &str) -> Option<&'a str> {
+    let mut parts = text.splitn(2, char::is_whitespace);
+    let command = parts.next()?;
+    let command = command
+        .strip_suffix(&format!("@{bot_username}"))
+        .unwrap_or(command);
+    (command == "/enroll").then(|| parts.next().unwrap_or("").trim_start())
+}
+
+/// Register an unauthenticated user via `/enroll <invite> <readeck_api_token>`:
+/// the supplied token is probed against Readeck, the invite is redeemed
+/// single-use, and on success a new account is persisted through the store.
+async fn enroll_handler(
+    api: &ReadeckApi,
+    users: &Arc<dyn UserStore>,
+    invites: &InviteStore,
+    user_id: u64,
+    args: &str,
+) -> String {
+    let mut parts = args.split_whitespace();
+    let (Some(code), Some(api_token)) = (parts.next(), parts.next()) else {
+        return "Usage: /enroll <invite> <readeck_api_token>".to_owned();
+    };
+    // Validate the token before touching the invite, so a typo doesn't claim it.
+    if let Err(e) = api.validate_token(api_token).await {
+        return format!("Could not validate your Readeck token: {e}");
+    }
+    // Atomically claim the single-use code; a concurrent enrollment racing on
+    // the same code will see it already used.
+    match invites.claim(code).await {
+        Ok(()) => {}
+        Err(InviteError::Unknown) => return "Unknown invite code.".to_owned(),
+        Err(e) => return format!("{e}"),
+    }
+    let data = UserData {
+        api_token: api_token.to_owned(),
+        bot_marked: true,
+    };
+    if let Err(e) = users.upsert(user_id, data).await {
+        // Roll the claim back so the code stays usable for a retry.
+        error!("Could not persist enrolled user {user_id}: {e}");
+        invites.release(code).await;
+        return "Enrollment failed while saving your account, please try again.".to_owned();
+    }
+    "You're enrolled! Send me a link to save it to Readeck.".to_owned()
+}
+
+/// Initialize the Sentry client when `SENTRY_DSN` is set, returning the guard
+/// that must outlive the process. A no-op (returning `None`) when unset, so
+/// telemetry is strictly opt-in.
+fn init_sentry() -> Option<sentry::ClientInitGuard> {
+    match env::var("SENTRY_DSN") {
+        Ok(dsn) if !dsn.is_empty() => {
+            let guard = sentry::init((
+                dsn,
+                sentry::ClientOptions {
+                    release: sentry::release_name!(),
+                    ..Default::default()
+                },
+            ));
+            info!("Sentry telemetry enabled");
+            Some(guard)
+        }
+        _ => None,
+    }
+}
+
+/// Derive a stable, non-reversible identifier from a Telegram user id so events
+/// can be correlated without recording who the user actually is.
+fn anonymize_user(user_id: u64) -> String {
+    let mut hasher = DefaultHasher::new();
+    user_id.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
 #[derive(Debug, Error)]
 enum ProcessError {
     #[error("Not a valid URL")]
     NotAnUrl,
-    #[error("{0}")]
-    ReadeckError(ReadeckError),
-    #[error("Article saved, but errors occurred: {0}")]
-    SavedWithError(ReadeckError),
+}
+
+/// How many saves run against Readeck at once, so a message with a long list
+/// of links doesn't hammer the server serially nor all at once.
+const MAX_CONCURRENT_SAVES: usize = 4;
+
+/// The result of trying to save one URL from a message, kept per-URL so the
+/// handler can build a consolidated report.
+enum SaveOutcome {
+    Saved {
+        url: Url,
+        title: Option<String>,
+        reading_time: Option<u32>,
+    },
+    Queued {
+        url: Url,
+    },
+    Failed {
+        url: Url,
+        error: ReadeckError,
+    },
 }
 
 async fn msg_handler(
     api: Arc<ReadeckApi>,
     msg: &Message,
     pretty_response: bool,
+    user_id: u64,
     user_data: &UserData,
+    queue: Arc<RetryQueue>,
 ) -> Result<String, ProcessError> {
-    let url = extract_url(msg).ok_or(ProcessError::NotAnUrl)??;
-    let bookmark_id = api
-        .save_url(url, &user_data.api_token, user_data.bot_marked)
+    let urls = extract_urls(msg);
+    if urls.is_empty() {
+        return Err(ProcessError::NotAnUrl);
+    }
+    let chat_id = msg.chat.id.0;
+    let outcomes: Vec<SaveOutcome> = stream::iter(urls)
+        .map(|url| {
+            let api = &api;
+            let queue = &queue;
+            async move {
+                save_one(api, url, pretty_response, user_id, chat_id, user_data, queue).await
+            }
+        })
+        .buffered(MAX_CONCURRENT_SAVES)
+        .collect()
+        .await;
+    Ok(format_report(outcomes))
+}
+
+/// The label set a default save uses, mirroring `ReadeckApi::save_url`.
+fn bot_labels(bot_marked: bool) -> Vec<String> {
+    if bot_marked {
+        vec![readeckbot::BOT_LABEL.to_owned()]
+    } else {
+        vec![]
+    }
+}
+
+/// Save a single URL, enqueuing it for retry on a transient error and fetching
+/// its details when a pretty response is requested.
+async fn save_one(
+    api: &ReadeckApi,
+    url: Url,
+    pretty_response: bool,
+    user_id: u64,
+    chat_id: i64,
+    user_data: &UserData,
+    queue: &RetryQueue,
+) -> SaveOutcome {
+    let bookmark_id = match api
+        .save_url(url.clone(), &user_data.api_token, user_data.bot_marked)
         .await
-        .map_err(ProcessError::ReadeckError)?;
+    {
+        Ok(id) => id,
+        // Transient failures are handed to the durable retry queue rather than
+        // lost: the worker will re-attempt and notify the user on the outcome.
+        Err(e) if e.is_retryable() => {
+            queue
+                .enqueue(user_id, chat_id, url.clone(), bot_labels(user_data.bot_marked))
+                .await;
+            return SaveOutcome::Queued { url };
+        }
+        Err(error) => return SaveOutcome::Failed { url, error },
+    };
     if pretty_response {
-        let bookmark_details = api
+        match api
             .get_bookmark_details(bookmark_id, &user_data.api_token)
             .await
-            .map_err(ProcessError::SavedWithError)?;
-        let response = if !bookmark_details.title.is_empty() {
-            match bookmark_details.reading_time {
-                Some(reading_time) => format!(
-                    "{} added to Readeck.\n\n Reading time: {}",
-                    bookmark_details.title, reading_time
+        {
+            Ok(details) => SaveOutcome::Saved {
+                url,
+                title: Some(details.title).filter(|t| !t.is_empty()),
+                reading_time: details.reading_time,
+            },
+            // The article is saved; only the metadata lookup failed.
+            Err(_) => SaveOutcome::Saved {
+                url,
+                title: None,
+                reading_time: None,
+            },
+        }
+    } else {
+        SaveOutcome::Saved {
+            url,
+            title: None,
+            reading_time: None,
+        }
+    }
+}
+
+/// Render one line per URL, so a user who pastes a list sees exactly which
+/// links were saved and which failed.
+fn format_report(outcomes: Vec<SaveOutcome>) -> String {
+    if outcomes.len() == 1 {
+        // Keep the familiar single-link wording when there's nothing to batch.
+        return match outcomes.into_iter().next().unwrap() {
+            SaveOutcome::Saved {
+                title,
+                reading_time,
+                ..
+            } => match (title, reading_time) {
+                (Some(title), Some(reading_time)) => format!(
+                    "{title} added to Readeck.\n\n Reading time: {reading_time}"
                 ),
-                None => format!("{} added to Readeck.", bookmark_details.title),
+                (Some(title), None) => format!("{title} added to Readeck."),
+                (None, _) => "Added to Readeck.".to_owned(),
+            },
+            SaveOutcome::Queued { .. } => {
+                "Readeck is unreachable right now, queued for retry.".to_owned()
             }
-        } else {
-            "Added to Readeck.".to_owned()
+            SaveOutcome::Failed { error, .. } => format!("{error}"),
         };
-        Ok(response)
-    } else {
-        Ok("Article saved successfully".to_owned())
     }
+    let lines: Vec<String> = outcomes
+        .into_iter()
+        .map(|outcome| match outcome {
+            SaveOutcome::Saved {
+                url,
+                title,
+                reading_time,
+            } => {
+                let label = title.unwrap_or_else(|| url.to_string());
+                match reading_time {
+                    Some(reading_time) => {
+                        format!("\u{2705} {label} (reading time: {reading_time})")
+                    }
+                    None => format!("\u{2705} {label}"),
+                }
+            }
+            SaveOutcome::Queued { url } => {
+                format!("\u{23f3} {url} — Readeck unreachable, queued for retry")
+            }
+            SaveOutcome::Failed { url, error } => format!("\u{274c} {url} — {error}"),
+        })
+        .collect();
+    lines.join("\n")
 }
 
-fn extract_url(msg: &Message) -> Option<Result<Url, ProcessError>> {
-    let mut urls: Vec<Result<Url, ProcessError>> = vec![];
+/// Collect every URL referenced by a message — text-link entities, plain text,
+/// and captions — deduplicated with first-seen order preserved.
+fn extract_urls(msg: &Message) -> Vec<Url> {
+    let mut urls: Vec<Url> = vec![];
     if let Some(entities) = msg.parse_entities() {
-        let mut parsed_text_links: Vec<Result<Url, ProcessError>> = entities
-            .into_iter()
-            .filter_map(|e| {
-                if let MessageEntityKind::TextLink { url } = e.kind() {
-                    Some(Ok(url.to_owned()))
-                } else {
-                    None
-                }
-            })
-            .collect();
-        urls.append(&mut parsed_text_links);
+        for e in entities {
+            if let MessageEntityKind::TextLink { url } = e.kind() {
+                urls.push(url.to_owned());
+            }
+        }
     }
     if let Some(entities) = msg.parse_caption_entities() {
-        let mut parsed_caption_links: Vec<Result<Url, ProcessError>> = entities
-            .into_iter()
-            .filter_map(|e| {
-                if let MessageEntityKind::TextLink { url } = e.kind() {
-                    Some(Ok(url.to_owned()))
-                } else {
-                    None
-                }
-            })
-            .collect();
-        urls.append(&mut parsed_caption_links);
+        for e in entities {
+            if let MessageEntityKind::TextLink { url } = e.kind() {
+                urls.push(url.to_owned());
+            }
+        }
     }
-    let msg_text = msg.text().unwrap_or("");
-    let mut parsed_msg_text = parse_url(msg_text);
-    urls.append(&mut parsed_msg_text);
-    let caption_text = msg.caption().unwrap_or("");
-    let mut parsed_caption_text = parse_url(caption_text);
-    urls.append(&mut parsed_caption_text);
-    urls.into_iter().next()
+    urls.append(&mut parse_url(msg.text().unwrap_or("")));
+    urls.append(&mut parse_url(msg.caption().unwrap_or("")));
+    dedup_urls(urls)
 }
 
-fn parse_url(text: &str) -> Vec<Result<Url, ProcessError>> {
+/// Drop duplicate URLs, keeping the first occurrence so paste order is
+/// preserved.
+fn dedup_urls(mut urls: Vec<Url>) -> Vec<Url> {
+    let mut seen = HashSet::new();
+    urls.retain(|url| seen.insert(url.to_string()));
+    urls
+}
+
+fn parse_url(text: &str) -> Vec<Url> {
     Regex::new(r"https?:\/\/(www\.)?[-a-zA-Z0-9@:%._\+~#=]{2,256}\.[a-z]{2,4}\b([-a-zA-Z0-9@:%_\+.~#?&//=]*)")
         .unwrap()
         .find_iter(text)
-        .map(|m| m.as_str())
-        .map(|s| Url::parse(s).map_err(|_| ProcessError::NotAnUrl))
+        .filter_map(|m| Url::parse(m.as_str()).ok())
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn dedup_preserves_first_seen_order() {
+        let input = vec![
+            url("https://a.example/1"),
+            url("https://b.example/2"),
+            url("https://a.example/1"),
+            url("https://c.example/3"),
+        ];
+        let result = dedup_urls(input);
+        assert_eq!(
+            result,
+            vec![
+                url("https://a.example/1"),
+                url("https://b.example/2"),
+                url("https://c.example/3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_outcome_keeps_legacy_wording() {
+        let report = format_report(vec![SaveOutcome::Saved {
+            url: url("https://a.example/1"),
+            title: Some("My Article".to_owned()),
+            reading_time: Some(5),
+        }]);
+        assert_eq!(report, "My Article added to Readeck.\n\n Reading time: 5");
+    }
+
+    #[test]
+    fn batch_outcome_lists_one_line_per_url() {
+        let report = format_report(vec![
+            SaveOutcome::Saved {
+                url: url("https://a.example/1"),
+                title: Some("First".to_owned()),
+                reading_time: None,
+            },
+            SaveOutcome::Queued {
+                url: url("https://b.example/2"),
+            },
+        ]);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("First"));
+        assert!(lines[1].contains("queued for retry"));
+    }
+}
@@ -0,0 +1,325 @@
+/*
+ * Copyright (c) 2025 Matteo Franceschini
+ * All rights reserved.
+ *
+ * Use of this source code is governed by BSD-3-Clause-Clear
+ * license that can be found in the LICENSE file
+ */
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info, warn};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use teloxide::{prelude::*, types::ChatId};
+use tokio::sync::Mutex;
+
+use crate::{ReadeckApi, users::UserStore};
+
+/// A single save that failed with a transient error and is waiting to be
+/// re-dispatched by the [`RetryWorker`].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RetryJob {
+    /// Unique identifier so two failed saves of the same URL by one user stay
+    /// distinct jobs.
+    pub id: String,
+    pub user_id: u64,
+    pub chat_id: i64,
+    pub url: Url,
+    /// The exact label set the save was made with, so retried `/label` saves
+    /// keep the user's custom labels instead of only the bot marker.
+    pub labels: Vec<String>,
+    pub attempts: u32,
+    /// Unix timestamp (seconds) before which the job must not be retried.
+    pub next_attempt_at: u64,
+}
+
+/// Tunables controlling how aggressively failed saves are retried.
+#[derive(Clone, Copy)]
+pub struct RetryConfig {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: u32,
+    pub tick_interval: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            base_delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(3600),
+            max_attempts: 8,
+            tick_interval: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Durable, on-disk queue of failed saves.
+///
+/// The backing file lives next to `USERS_FILE` and is rewritten on every
+/// mutation, so the queue survives restarts: [`RetryQueue::load`] replays it at
+/// startup and the [`RetryWorker`] keeps draining due jobs from where it left
+/// off.
+pub struct RetryQueue {
+    path: PathBuf,
+    config: RetryConfig,
+    jobs: Mutex<Vec<RetryJob>>,
+    seq: AtomicU64,
+}
+
+impl RetryQueue {
+    /// Load the persisted queue from `path`, starting empty when the file is
+    /// missing or unreadable (a corrupt queue must not keep the bot down).
+    pub async fn load<P: AsRef<Path>>(path: P, config: RetryConfig) -> RetryQueue {
+        let path = path.as_ref().to_path_buf();
+        let jobs = match tokio::fs::read_to_string(&path).await {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                warn!("Could not parse retry queue, starting empty: {e}");
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+        RetryQueue {
+            path,
+            config,
+            jobs: Mutex::new(jobs),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Mint a process-unique job id from the wall clock and a monotonic counter.
+    fn next_id(&self) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        format!("{nanos:x}-{seq:x}")
+    }
+
+    async fn persist(&self, jobs: &[RetryJob]) {
+        match serde_json::to_string(jobs) {
+            Ok(data) => {
+                if let Err(e) = tokio::fs::write(&self.path, data).await {
+                    error!("Could not persist retry queue: {e}");
+                }
+            }
+            Err(e) => error!("Could not serialize retry queue: {e}"),
+        }
+    }
+
+    /// Enqueue a freshly failed save, scheduling its first retry after
+    /// `base_delay`. `labels` is the label set the save was originally made
+    /// with, preserved across retries.
+    pub async fn enqueue(&self, user_id: u64, chat_id: i64, url: Url, labels: Vec<String>) {
+        let job = RetryJob {
+            id: self.next_id(),
+            user_id,
+            chat_id,
+            url,
+            labels,
+            attempts: 0,
+            next_attempt_at: now() + self.config.base_delay.as_secs(),
+        };
+        let mut jobs = self.jobs.lock().await;
+        jobs.push(job);
+        self.persist(&jobs).await;
+    }
+}
+
+/// Background task that drains due jobs from a [`RetryQueue`] and re-attempts
+/// the save, mirroring the webmention-delivery worker pattern.
+pub struct RetryWorker {
+    queue: Arc<RetryQueue>,
+    api: Arc<ReadeckApi>,
+    users: Arc<dyn UserStore>,
+    bot: Bot,
+}
+
+impl RetryWorker {
+    pub fn new(
+        queue: Arc<RetryQueue>,
+        api: Arc<ReadeckApi>,
+        users: Arc<dyn UserStore>,
+        bot: Bot,
+    ) -> RetryWorker {
+        RetryWorker {
+            queue,
+            api,
+            users,
+            bot,
+        }
+    }
+
+    /// Run forever, waking on `tick_interval` to process every job whose
+    /// `next_attempt_at` has passed.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.queue.config.tick_interval);
+        loop {
+            interval.tick().await;
+            self.drain_due().await;
+        }
+    }
+
+    async fn drain_due(&self) {
+        let due: Vec<RetryJob> = {
+            let jobs = self.queue.jobs.lock().await;
+            jobs.iter()
+                .filter(|j| j.next_attempt_at <= now())
+                .cloned()
+                .collect()
+        };
+        for job in due {
+            self.dispatch(job).await;
+        }
+    }
+
+    async fn dispatch(&self, mut job: RetryJob) {
+        let user = match self.users.find(job.user_id).await {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                // The user was removed while the job waited; drop it silently.
+                self.remove(&job).await;
+                return;
+            }
+            Err(e) => {
+                // Store unreachable — leave the job for a later tick.
+                warn!("Could not load user {} for retry: {e}", job.user_id);
+                return;
+            }
+        };
+        match self
+            .api
+            .save_url_with_labels(job.url.clone(), &user.api_token, job.labels.clone())
+            .await
+        {
+            Ok(_) => {
+                info!("Retried save for user {} succeeded", job.user_id);
+                self.remove(&job).await;
+                self.notify(job.chat_id, format!("{} finally saved to Readeck.", job.url))
+                    .await;
+            }
+            Err(e) if e.is_retryable() && job.attempts + 1 < self.queue.config.max_attempts => {
+                job.attempts += 1;
+                job.next_attempt_at = now() + self.backoff(job.attempts);
+                self.update(job).await;
+            }
+            Err(e) => {
+                warn!("Dropping save for user {} after failure: {e}", job.user_id);
+                // Capture the incident once, here at final give-up: retryable
+                // save errors are not reported by `ReadeckApi` on each attempt,
+                // while other errors were already reported by the save call.
+                if e.is_retryable() {
+                    sentry::capture_error(&e);
+                }
+                self.remove(&job).await;
+                self.notify(
+                    job.chat_id,
+                    format!("Gave up saving {} to Readeck: {e}", job.url),
+                )
+                .await;
+            }
+        }
+    }
+
+    /// The capped exponential delay plus a small jitter so retries from many
+    /// jobs don't thunder together.
+    fn backoff(&self, attempts: u32) -> u64 {
+        let delay = backoff_delay(&self.queue.config, attempts);
+        delay + jitter(delay)
+    }
+
+    async fn remove(&self, job: &RetryJob) {
+        let mut jobs = self.queue.jobs.lock().await;
+        jobs.retain(|j| !same_job(j, job));
+        self.queue.persist(&jobs).await;
+    }
+
+    async fn update(&self, job: RetryJob) {
+        let mut jobs = self.queue.jobs.lock().await;
+        if let Some(slot) = jobs.iter_mut().find(|j| same_job(j, &job)) {
+            *slot = job;
+        }
+        self.queue.persist(&jobs).await;
+    }
+
+    async fn notify(&self, chat_id: i64, text: String) {
+        if let Err(e) = self.bot.send_message(ChatId(chat_id), text).await {
+            warn!("Could not notify user about retry outcome: {e}");
+        }
+    }
+}
+
+fn same_job(a: &RetryJob, b: &RetryJob) -> bool {
+    a.id == b.id
+}
+
+/// `base_delay * 2^attempts` seconds, saturating and capped at `max_delay`.
+fn backoff_delay(config: &RetryConfig, attempts: u32) -> u64 {
+    let base = config.base_delay.as_secs();
+    let cap = config.max_delay.as_secs();
+    base.saturating_mul(1u64 << attempts.min(16)).min(cap)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Up to ~25% of `delay`, derived from the wall clock so it varies per call
+/// without pulling in an RNG dependency.
+fn jitter(delay: u64) -> u64 {
+    if delay == 0 {
+        return 0;
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    (nanos % (delay / 4 + 1)).min(delay / 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RetryConfig {
+        RetryConfig {
+            base_delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(3600),
+            max_attempts: 8,
+            tick_interval: Duration::from_secs(15),
+        }
+    }
+
+    #[test]
+    fn backoff_grows_exponentially() {
+        let config = config();
+        assert_eq!(backoff_delay(&config, 0), 30);
+        assert_eq!(backoff_delay(&config, 1), 60);
+        assert_eq!(backoff_delay(&config, 2), 120);
+    }
+
+    #[test]
+    fn backoff_is_capped_at_max_delay() {
+        let config = config();
+        // 30 * 2^20 would overflow the hour cap many times over.
+        assert_eq!(backoff_delay(&config, 20), 3600);
+    }
+
+    #[test]
+    fn jitter_never_exceeds_a_quarter_of_the_delay() {
+        for delay in [0, 1, 30, 120, 3600] {
+            assert!(jitter(delay) <= delay / 4);
+        }
+    }
+}
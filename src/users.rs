@@ -6,28 +6,338 @@
  * license that can be found in the LICENSE file
  */
 
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
 
-use serde::Deserialize;
+use async_trait::async_trait;
+use bb8_redis::{RedisConnectionManager, bb8::Pool, redis::AsyncCommands};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use tokio::sync::RwLock;
 
-#[derive(Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct UserData {
     pub api_token: String,
     pub bot_marked: bool,
 }
 
-#[derive(Deserialize)]
-pub struct Users {
+/// Errors that a [`UserStore`] backend can surface. Lookups never fail the
+/// whole bot anymore — a backend that cannot be reached returns an error the
+/// caller decides how to handle.
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("An I/O error occurred: {0}")]
+    Io(std::io::Error),
+    #[error("A serialization/deserialization error occurred: {0}")]
+    Serde(serde_json::Error),
+    #[error("A Redis error occurred: {0}")]
+    Redis(String),
+    #[error("A database error occurred: {0}")]
+    Database(String),
+}
+
+/// Abstract, async-backed table of enrolled users.
+///
+/// Backends are selected at startup (see [`build_store`]); the handler only
+/// ever sees an `Arc<dyn UserStore>`, so multiple bot instances can share a
+/// Redis- or Postgres-backed table without code changes.
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn find(&self, user_id: u64) -> Result<Option<UserData>, StoreError>;
+    async fn upsert(&self, user_id: u64, data: UserData) -> Result<(), StoreError>;
+    async fn remove(&self, user_id: u64) -> Result<(), StoreError>;
+}
+
+/// Construct the backend named by `USER_STORE` (`file` by default), falling
+/// back to the file store rooted at `users_file` when no explicit backend is
+/// configured.
+pub async fn build_store(users_file: &str) -> Arc<dyn UserStore> {
+    let backend = std::env::var("USER_STORE").unwrap_or_else(|_| "file".to_owned());
+    match backend.as_str() {
+        "memory" => Arc::new(MemoryStore::default()),
+        "redis" => {
+            let url =
+                std::env::var("REDIS_URL").expect("REDIS_URL must be set when USER_STORE=redis");
+            match RedisStore::connect(&url).await {
+                Ok(store) => Arc::new(store),
+                Err(e) => panic!("Could not connect to Redis user store: {e}"),
+            }
+        }
+        "postgres" => {
+            let url = std::env::var("DATABASE_URL")
+                .expect("DATABASE_URL must be set when USER_STORE=postgres");
+            match PostgresStore::connect(&url).await {
+                Ok(store) => Arc::new(store),
+                Err(e) => panic!("Could not connect to Postgres user store: {e}"),
+            }
+        }
+        "file" => Arc::new(FileStore::new(users_file)),
+        other => {
+            warn!("Unknown USER_STORE '{other}', falling back to file backend");
+            Arc::new(FileStore::new(users_file))
+        }
+    }
+}
+
+/// File-backed store that reloads `users.json` when it changes on disk instead
+/// of panicking on a parse error, so a bad edit no longer takes the bot down.
+pub struct FileStore {
+    path: PathBuf,
+    cache: RwLock<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
     users: HashMap<u64, UserData>,
+    loaded_at: Option<SystemTime>,
+    /// Whether a load has been attempted, so an absent file is treated as an
+    /// empty store rather than re-read (and re-logged) on every lookup.
+    loaded: bool,
+}
+
+/// Read a file's modification time without blocking the runtime.
+async fn mtime(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.and_then(|m| m.modified()).ok()
+}
+
+impl FileStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileStore {
+        FileStore {
+            path: path.as_ref().to_path_buf(),
+            cache: RwLock::new(Cache::default()),
+        }
+    }
+
+    /// Reload the backing file if its modification time advanced since the last
+    /// read. A missing file is treated as an empty store; a read or parse
+    /// failure is logged and leaves the current cache in place rather than
+    /// crashing.
+    async fn refresh(&self) {
+        let mtime = mtime(&self.path).await;
+        {
+            let cache = self.cache.read().await;
+            if cache.loaded && cache.loaded_at == mtime {
+                return;
+            }
+        }
+        match tokio::fs::read_to_string(&self.path).await {
+            Ok(data) => match serde_json::from_str::<HashMap<u64, UserData>>(&data) {
+                Ok(users) => {
+                    let mut cache = self.cache.write().await;
+                    cache.users = users;
+                    cache.loaded_at = mtime;
+                    cache.loaded = true;
+                }
+                Err(e) => error!("Could not parse {}: {e}", self.path.display()),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // Not yet created — record the empty state so we don't re-read
+                // and re-log on every message until the file appears.
+                let mut cache = self.cache.write().await;
+                cache.users.clear();
+                cache.loaded_at = None;
+                cache.loaded = true;
+            }
+            Err(e) => error!("Could not read {}: {e}", self.path.display()),
+        }
+    }
+
+    async fn flush(&self, users: &HashMap<u64, UserData>) -> Result<(), StoreError> {
+        let data = serde_json::to_string_pretty(users).map_err(StoreError::Serde)?;
+        tokio::fs::write(&self.path, data)
+            .await
+            .map_err(StoreError::Io)?;
+        let mut cache = self.cache.write().await;
+        cache.loaded_at = mtime(&self.path).await;
+        cache.loaded = true;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserStore for FileStore {
+    async fn find(&self, user_id: u64) -> Result<Option<UserData>, StoreError> {
+        self.refresh().await;
+        let cache = self.cache.read().await;
+        Ok(cache.users.get(&user_id).cloned())
+    }
+
+    async fn upsert(&self, user_id: u64, data: UserData) -> Result<(), StoreError> {
+        self.refresh().await;
+        let mut cache = self.cache.write().await;
+        cache.users.insert(user_id, data);
+        let snapshot = cache.users.clone();
+        drop(cache);
+        self.flush(&snapshot).await
+    }
+
+    async fn remove(&self, user_id: u64) -> Result<(), StoreError> {
+        self.refresh().await;
+        let mut cache = self.cache.write().await;
+        cache.users.remove(&user_id);
+        let snapshot = cache.users.clone();
+        drop(cache);
+        self.flush(&snapshot).await
+    }
 }
 
-impl Users {
-    pub fn load<P: AsRef<Path>>(path: P) -> Users {
-        let data = std::fs::read_to_string(path).unwrap();
-        serde_json::from_str(&data).unwrap()
+/// In-memory store, handy for tests and single-instance deployments that don't
+/// need persistence.
+#[derive(Default)]
+pub struct MemoryStore {
+    users: RwLock<HashMap<u64, UserData>>,
+}
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn find(&self, user_id: u64) -> Result<Option<UserData>, StoreError> {
+        Ok(self.users.read().await.get(&user_id).cloned())
+    }
+
+    async fn upsert(&self, user_id: u64, data: UserData) -> Result<(), StoreError> {
+        self.users.write().await.insert(user_id, data);
+        Ok(())
+    }
+
+    async fn remove(&self, user_id: u64) -> Result<(), StoreError> {
+        self.users.write().await.remove(&user_id);
+        Ok(())
+    }
+}
+
+/// Redis-backed store sharing one user table across bot instances, using a
+/// pooled connection manager (`bb8-redis`).
+pub struct RedisStore {
+    pool: Pool<RedisConnectionManager>,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str) -> Result<RedisStore, StoreError> {
+        let manager =
+            RedisConnectionManager::new(url).map_err(|e| StoreError::Redis(e.to_string()))?;
+        let pool = Pool::builder()
+            .build(manager)
+            .await
+            .map_err(|e| StoreError::Redis(e.to_string()))?;
+        Ok(RedisStore { pool })
+    }
+
+    fn key(user_id: u64) -> String {
+        format!("readeckbot:user:{user_id}")
+    }
+}
+
+#[async_trait]
+impl UserStore for RedisStore {
+    async fn find(&self, user_id: u64) -> Result<Option<UserData>, StoreError> {
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .map_err(|e| StoreError::Redis(e.to_string()))?;
+        let raw: Option<String> = conn
+            .get(Self::key(user_id))
+            .await
+            .map_err(|e| StoreError::Redis(e.to_string()))?;
+        match raw {
+            Some(data) => Ok(Some(serde_json::from_str(&data).map_err(StoreError::Serde)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn upsert(&self, user_id: u64, data: UserData) -> Result<(), StoreError> {
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .map_err(|e| StoreError::Redis(e.to_string()))?;
+        let payload = serde_json::to_string(&data).map_err(StoreError::Serde)?;
+        conn.set::<_, _, ()>(Self::key(user_id), payload)
+            .await
+            .map_err(|e| StoreError::Redis(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn remove(&self, user_id: u64) -> Result<(), StoreError> {
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .map_err(|e| StoreError::Redis(e.to_string()))?;
+        conn.del::<_, ()>(Self::key(user_id))
+            .await
+            .map_err(|e| StoreError::Redis(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Postgres-backed store for deployments that already run a relational
+/// database, backed by a pooled `sqlx` connection.
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub async fn connect(url: &str) -> Result<PostgresStore, StoreError> {
+        let pool = PgPool::connect(url)
+            .await
+            .map_err(|e| StoreError::Database(e.to_string()))?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS users (
+                user_id BIGINT PRIMARY KEY,
+                api_token TEXT NOT NULL,
+                bot_marked BOOLEAN NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await
+        .map_err(|e| StoreError::Database(e.to_string()))?;
+        Ok(PostgresStore { pool })
+    }
+}
+
+#[async_trait]
+impl UserStore for PostgresStore {
+    async fn find(&self, user_id: u64) -> Result<Option<UserData>, StoreError> {
+        let row = sqlx::query_as::<_, (String, bool)>(
+            "SELECT api_token, bot_marked FROM users WHERE user_id = $1",
+        )
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| StoreError::Database(e.to_string()))?;
+        Ok(row.map(|(api_token, bot_marked)| UserData {
+            api_token,
+            bot_marked,
+        }))
+    }
+
+    async fn upsert(&self, user_id: u64, data: UserData) -> Result<(), StoreError> {
+        sqlx::query(
+            "INSERT INTO users (user_id, api_token, bot_marked) VALUES ($1, $2, $3)
+             ON CONFLICT (user_id) DO UPDATE
+             SET api_token = EXCLUDED.api_token, bot_marked = EXCLUDED.bot_marked",
+        )
+        .bind(user_id as i64)
+        .bind(data.api_token)
+        .bind(data.bot_marked)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| StoreError::Database(e.to_string()))?;
+        Ok(())
     }
 
-    pub fn find(&self, user_id: u64) -> Option<&UserData> {
-        self.users.get(&user_id)
+    async fn remove(&self, user_id: u64) -> Result<(), StoreError> {
+        sqlx::query("DELETE FROM users WHERE user_id = $1")
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| StoreError::Database(e.to_string()))?;
+        Ok(())
     }
 }